@@ -0,0 +1,90 @@
+// Hilbert curve conversions for a 2^n x 2^n grid: map between a 1-D index
+// along the curve and 2-D (x, y) grid coordinates of the same order `n`, so
+// that indices close together on the curve stay close together in space.
+// Standard d2xy/xy2d derivation (https://en.wikipedia.org/wiki/Hilbert_curve).
+
+pub fn hilbert_xy2d(n: u32, mut x: u32, mut y: u32) -> u64 {
+    let mut d: u64 = 0;
+    let side = 1u32 << n;
+    let mut s = side >> 1;
+
+    while s > 0 {
+        let rx = u32::from((x & s) > 0);
+        let ry = u32::from((y & s) > 0);
+        d += (s as u64) * (s as u64) * u64::from((3 * rx) ^ ry);
+        rotate(side, &mut x, &mut y, rx, ry);
+        s >>= 1;
+    }
+
+    d
+}
+
+pub fn hilbert_d2xy(n: u32, d: u64) -> (u32, u32) {
+    let mut x = 0u32;
+    let mut y = 0u32;
+    let mut t = d;
+    let mut s = 1u32;
+
+    while s < (1u32 << n) {
+        let rx = (1 & (t / 2)) as u32;
+        let ry = (1 & (t ^ rx as u64)) as u32;
+        rotate(s, &mut x, &mut y, rx, ry);
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s <<= 1;
+    }
+
+    (x, y)
+}
+
+// Rotates (and, along the x=y diagonal, reflects) the quadrant's coordinate
+// frame so the curve stays continuous across the quadrant boundary it just
+// crossed. `side` is the width of the square `x`/`y` are being reflected
+// within: the full grid for `hilbert_xy2d` (whose `x`/`y` are already at
+// final scale), or the current step size for `hilbert_d2xy` (whose `x`/`y`
+// are still being built up one scale at a time).
+fn rotate(side: u32, x: &mut u32, y: &mut u32, rx: u32, ry: u32) {
+    if ry == 0 {
+        if rx == 1 {
+            *x = side - 1 - *x;
+            *y = side - 1 - *y;
+        }
+        std::mem::swap(x, y);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xy2d_then_d2xy_roundtrips_every_cell() {
+        const N: u32 = 4;
+        let side = 1u32 << N;
+        for x in 0..side {
+            for y in 0..side {
+                let d = hilbert_xy2d(N, x, y);
+                assert_eq!(hilbert_d2xy(N, d), (x, y), "roundtrip failed for ({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn d2xy_then_xy2d_roundtrips_every_index() {
+        const N: u32 = 4;
+        let cell_count = 1u64 << (2 * N);
+        for d in 0..cell_count {
+            let (x, y) = hilbert_d2xy(N, d);
+            assert_eq!(hilbert_xy2d(N, x, y), d, "roundtrip failed for d={d}");
+        }
+    }
+
+    #[test]
+    fn screen_center_does_not_panic() {
+        // Regression: hilbert_xy2d used to subtract with overflow for most
+        // (x, y) pairs, including the screen center where every particle
+        // starts.
+        hilbert_xy2d(10, 512, 512);
+    }
+}
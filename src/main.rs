@@ -1,10 +1,19 @@
 #![windows_subsystem = "windows"]
 
+mod hilbert;
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
 use std::f32::consts::TAU;
+use std::sync::atomic::AtomicU64;
 
 use macroquad::{
-    prelude::{is_key_pressed, Circle, Color, KeyCode, Rect, Vec2, BLACK, BLUE, GRAY, GREEN, RED},
-    shapes::{draw_circle, draw_rectangle_lines},
+    input::mouse_position,
+    prelude::{
+        is_key_pressed, Circle, Color, KeyCode, Rect, Vec2, BLACK, BLUE, GRAY, GREEN, ORANGE,
+        PURPLE, RED, SKYBLUE, YELLOW,
+    },
+    shapes::{draw_circle, draw_circle_lines, draw_line, draw_rectangle_lines},
     text::{draw_text_ex, TextParams},
     time::get_fps,
     window::{clear_background, next_frame, screen_height, screen_width, Conf},
@@ -12,21 +21,102 @@ use macroquad::{
 
 use rand::Rng;
 
+use hilbert::{hilbert_d2xy, hilbert_xy2d};
+
 const WINDOW_HEIGHT: f32 = 1024.0;
 const WINDOW_WIDTH: f32 = 1024.0;
 
 const NUMBER_OF_POINTS: f32 = 2000.0;
 const POINT_RADIUS: f32 = 5.0;
-const POINT_COLOR: Color = RED;
 
 const QUADTREE_CAPACITY: f32 = 30.0;
+const QUADTREE_MAX_DEPTH: u32 = 8;
 const RANDOM_WALK_DISTANCE: f32 = 10.0;
+const PARTICLE_SPEED: f32 = 10.0;
+const DEBUG_QUERY_RADIUS: f32 = 60.0;
+const DEBUG_NODE_COLOR: Color = YELLOW;
+
+const NEAREST_K: usize = 12;
+const NEAREST_HIGHLIGHT_COLOR: Color = SKYBLUE;
+
+// Each particle's `layer` is one bit of this mask, e.g. `players` vs.
+// `obstacles`. A query's mask is ANDed against it so only particles sharing
+// a layer bit are considered, following the `collisionFlags` filtering idea.
+const LAYER_COLORS: [Color; 3] = [RED, ORANGE, PURPLE];
+const ALL_LAYERS: u32 = u32::MAX;
+
+fn layer_color(layer: u32) -> Color {
+    LAYER_COLORS[layer.trailing_zeros() as usize % LAYER_COLORS.len()]
+}
+
+// Order of the Hilbert curve particle positions are mapped onto: a
+// 2^HILBERT_ORDER x 2^HILBERT_ORDER grid.
+const HILBERT_ORDER: u32 = 10;
+const HILBERT_MAX_INDEX: u64 = (1u64 << (2 * HILBERT_ORDER)) - 1;
+
+// Scales a screen position into the Hilbert grid and returns its index
+// along the curve, so particles near each other in space land near each
+// other on the curve (and vice versa).
+fn hilbert_index(pos: Vec2) -> u64 {
+    let grid_size = (1u32 << HILBERT_ORDER) as f32;
+    let x = (pos.x / screen_width() * grid_size).clamp(0.0, grid_size - 1.0) as u32;
+    let y = (pos.y / screen_height() * grid_size).clamp(0.0, grid_size - 1.0) as u32;
+    hilbert_xy2d(HILBERT_ORDER, x, y)
+}
+
+// Colors a particle along a gradient keyed by its position on the curve, so
+// spatial locality (nearby particles sharing nearby indices) becomes visible.
+fn hilbert_color(index: u64) -> Color {
+    let t = index as f32 / HILBERT_MAX_INDEX as f32;
+    Color::new(t, 0.4, 1.0 - t, 1.0)
+}
+
+// Sorts particles by Hilbert index before they're inserted into a fresh
+// forest, so spatially nearby particles land in the same slot's tree,
+// improving node occupancy and cache behavior over insertion order.
+fn sort_by_hilbert_index(points: &mut [Particle]) {
+    points.sort_by_key(|p| hilbert_index(p.pos));
+}
+
+// Coarse order used to trace the curve itself onto the screen: enough
+// cells to make the path recognizable without drawing a segment per
+// HILBERT_ORDER-grid cell every frame.
+const HILBERT_CURVE_PREVIEW_ORDER: u32 = 5;
+
+// Walks every index of a HILBERT_CURVE_PREVIEW_ORDER-grid back to its (x, y)
+// cell via `hilbert_d2xy` and scales each cell to screen space, so the
+// hilbert_color_mode overlay can draw the actual curve the particles are
+// colored by, not just the colors it produces.
+fn hilbert_curve_preview_points() -> Vec<Vec2> {
+    let cells_per_side = 1u32 << HILBERT_CURVE_PREVIEW_ORDER;
+    let cell_w = screen_width() / cells_per_side as f32;
+    let cell_h = screen_height() / cells_per_side as f32;
+    let cell_count = 1u64 << (2 * HILBERT_CURVE_PREVIEW_ORDER);
+
+    (0..cell_count)
+        .map(|d| {
+            let (x, y) = hilbert_d2xy(HILBERT_CURVE_PREVIEW_ORDER, d);
+            Vec2::new((x as f32 + 0.5) * cell_w, (y as f32 + 0.5) * cell_h)
+        })
+        .collect()
+}
+
+// Monotonic source for `Particle::id`, which the quadtree forest uses to
+// find a moved particle's current slot so it can be tombstoned and reinserted.
+static NEXT_PARTICLE_ID: AtomicU64 = AtomicU64::new(0);
+
+fn next_particle_id() -> u64 {
+    NEXT_PARTICLE_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+}
 
 #[derive(PartialEq, Copy, Clone, Debug)]
 struct Particle {
+    id: u64,
     pos: Vec2,
+    velocity: Vec2,
     color: Color,
     radius: f32,
+    layer: u32,
 }
 
 impl Particle {
@@ -39,10 +129,63 @@ impl Particle {
             + (self.pos.y - other.pos.y) * (self.pos.y - other.pos.y)
             < (self.radius + other.radius) * (self.radius + other.radius)
     }
+
+    fn bounding_box(&self) -> Rect {
+        Rect::new(
+            self.pos.x - self.radius,
+            self.pos.y - self.radius,
+            self.radius * 2.0,
+            self.radius * 2.0,
+        )
+    }
+}
+
+// A single candidate in the bounded max-heap `nearest` keeps while it
+// searches: ordered by squared distance so the worst-so-far candidate is
+// always the one popped when a closer particle is found.
+struct NearestCandidate {
+    dist_sq: f32,
+    particle: Particle,
+}
+
+impl PartialEq for NearestCandidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.dist_sq == other.dist_sq
+    }
+}
+
+impl Eq for NearestCandidate {}
+
+impl PartialOrd for NearestCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NearestCandidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.dist_sq
+            .partial_cmp(&other.dist_sq)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+// Shared by `QuadTree::nearest` and `QuadForest::nearest`: both fill a
+// bounded max-heap during their search, then just need it drained into
+// particles ordered nearest-first.
+fn nearest_heap_into_particles(heap: BinaryHeap<NearestCandidate>) -> Vec<Particle> {
+    let mut candidates: Vec<NearestCandidate> = heap.into_vec();
+    candidates.sort();
+    candidates
+        .into_iter()
+        .map(|candidate| candidate.particle)
+        .collect()
 }
 
 struct QuadTree {
     capacity: f32,
+    max_depth: u32,
+    depth: u32,
     is_full: bool,
     boundary: Rect,
     data: Vec<Particle>,
@@ -53,9 +196,15 @@ struct QuadTree {
 }
 
 impl QuadTree {
-    pub fn new(capacity: f32, boundary: Rect) -> QuadTree {
+    pub fn new(capacity: f32, boundary: Rect, max_depth: u32) -> QuadTree {
+        QuadTree::with_depth(capacity, boundary, max_depth, 0)
+    }
+
+    fn with_depth(capacity: f32, boundary: Rect, max_depth: u32, depth: u32) -> QuadTree {
         return QuadTree {
             capacity: capacity,
+            max_depth: max_depth,
+            depth: depth,
             is_full: false,
             boundary: boundary,
             data: Vec::new(),
@@ -68,75 +217,204 @@ impl QuadTree {
 
     pub fn insert(&mut self, point: Particle) {
         if self.is_full {
-            if self.northeast.is_some() {
-                let northeast = self.northeast.as_mut().unwrap();
-                if northeast.contains(&point) {
-                    northeast.insert(point);
-                    return;
+            self.insert_into_child(point);
+            return;
+        }
+
+        if self.data.len() as f32 >= self.capacity {
+            // Nodes this deep or whose boundary has collapsed to (near) nothing
+            // stay leaves forever rather than subdividing, so coincident points
+            // can't recurse the tree to a stack overflow.
+            if self.depth >= self.max_depth || self.boundary.w < 1.0 || self.boundary.h < 1.0 {
+                self.data.push(point);
+                return;
+            }
+
+            self.subdivide();
+
+            let buffered: Vec<Particle> = self.data.drain(..).collect();
+            for buffered_point in buffered {
+                self.insert_into_child(buffered_point);
+            }
+            self.insert_into_child(point);
+            return;
+        }
+
+        self.data.push(point);
+    }
+
+    fn subdivide(&mut self) {
+        self.is_full = true;
+        let x = self.boundary.x;
+        let y = self.boundary.y;
+        let w_2 = self.boundary.clone().w / 2.0;
+        let h_2 = self.boundary.clone().h / 2.0;
+        let child_depth = self.depth + 1;
+
+        self.northeast = Some(Box::new(QuadTree::with_depth(
+            self.capacity,
+            Rect::new(x + w_2, y, w_2, h_2),
+            self.max_depth,
+            child_depth,
+        )));
+        self.northwest = Some(Box::new(QuadTree::with_depth(
+            self.capacity,
+            Rect::new(x, y, w_2, h_2),
+            self.max_depth,
+            child_depth,
+        )));
+        self.southeast = Some(Box::new(QuadTree::with_depth(
+            self.capacity,
+            Rect::new(x + w_2, y + h_2, w_2, h_2),
+            self.max_depth,
+            child_depth,
+        )));
+        self.southwest = Some(Box::new(QuadTree::with_depth(
+            self.capacity,
+            Rect::new(x, y + h_2, w_2, h_2),
+            self.max_depth,
+            child_depth,
+        )));
+    }
+
+    fn insert_into_child(&mut self, point: Particle) {
+        if self.northeast.is_some() {
+            let northeast = self.northeast.as_mut().unwrap();
+            if northeast.contains(&point) {
+                northeast.insert(point);
+                return;
+            }
+        }
+        if self.northwest.is_some() {
+            let northwest = self.northwest.as_mut().unwrap();
+            if northwest.contains(&point) {
+                northwest.insert(point);
+                return;
+            }
+        }
+        if self.southeast.is_some() {
+            let southeast = self.southeast.as_mut().unwrap();
+            if southeast.contains(&point) {
+                southeast.insert(point);
+                return;
+            }
+        }
+        if self.southwest.is_some() {
+            let southwest = self.southwest.as_mut().unwrap();
+            if southwest.contains(&point) {
+                southwest.insert(point);
+                return;
+            }
+        }
+    }
+
+    fn contains(&self, point: &Particle) -> bool {
+        self.boundary.contains(point.pos)
+    }
+
+    // Descends exactly as `insert` would and returns the boundary of the
+    // leaf a particle at `pos` would land in, without inserting anything.
+    fn leaf_boundary(&self, pos: Vec2) -> Rect {
+        if self.is_full {
+            if let Some(v) = &self.northeast {
+                if v.boundary.contains(pos) {
+                    return v.leaf_boundary(pos);
                 }
             }
-            if self.northwest.is_some() {
-                let northwest = self.northwest.as_mut().unwrap();
-                if northwest.contains(&point) {
-                    northwest.insert(point);
-                    return;
+            if let Some(v) = &self.northwest {
+                if v.boundary.contains(pos) {
+                    return v.leaf_boundary(pos);
                 }
             }
-            if self.southeast.is_some() {
-                let southeast = self.southeast.as_mut().unwrap();
-                if southeast.contains(&point) {
-                    southeast.insert(point);
-                    return;
+            if let Some(v) = &self.southeast {
+                if v.boundary.contains(pos) {
+                    return v.leaf_boundary(pos);
                 }
             }
-            if self.southwest.is_some() {
-                let southwest = self.southwest.as_mut().unwrap();
-                if southwest.contains(&point) {
-                    southwest.insert(point);
-                    return;
+            if let Some(v) = &self.southwest {
+                if v.boundary.contains(pos) {
+                    return v.leaf_boundary(pos);
                 }
             }
-            return;
         }
+        self.boundary
+    }
 
-        if self.data.len() as f32 >= self.capacity {
-            self.is_full = true;
-            let x = self.boundary.x;
-            let y = self.boundary.y;
-            let w_2 = self.boundary.clone().w / 2.0;
-            let h_2 = self.boundary.clone().h / 2.0;
-
-            self.northeast = Some(Box::new(QuadTree::new(
-                self.capacity,
-                Rect::new(x + w_2, y, w_2, h_2),
-            )));
-            self.northwest = Some(Box::new(QuadTree::new(
-                self.capacity,
-                Rect::new(x, y, w_2, h_2),
-            )));
-            self.southeast = Some(Box::new(QuadTree::new(
-                self.capacity,
-                Rect::new(x + w_2, y + h_2, w_2, h_2),
-            )));
-            self.southwest = Some(Box::new(QuadTree::new(
-                self.capacity,
-                Rect::new(x, y + h_2, w_2, h_2),
-            )));
-
-            // for index in 0..self.data.len() {
-            //     self.insert(self.data[index]);
-            // }
-            return;
+    // Overwrites the entry with id `id` in place, descending to whichever
+    // leaf `updated.pos` now falls in. Callers only use this once they've
+    // already confirmed the particle is still within its cached leaf
+    // boundary, so the lookup below is expected to succeed.
+    fn update_in_place(&mut self, id: u64, updated: Particle) -> bool {
+        if self.is_full {
+            if let Some(v) = self.northeast.as_mut() {
+                if v.boundary.contains(updated.pos) {
+                    return v.update_in_place(id, updated);
+                }
+            }
+            if let Some(v) = self.northwest.as_mut() {
+                if v.boundary.contains(updated.pos) {
+                    return v.update_in_place(id, updated);
+                }
+            }
+            if let Some(v) = self.southeast.as_mut() {
+                if v.boundary.contains(updated.pos) {
+                    return v.update_in_place(id, updated);
+                }
+            }
+            if let Some(v) = self.southwest.as_mut() {
+                if v.boundary.contains(updated.pos) {
+                    return v.update_in_place(id, updated);
+                }
+            }
+            return false;
         }
 
-        self.data.push(point);
+        for p in self.data.iter_mut() {
+            if p.id == id {
+                *p = updated;
+                return true;
+            }
+        }
+        false
     }
 
-    fn contains(&self, point: &Particle) -> bool {
-        self.boundary.contains(point.pos)
+    // Physically removes the entry with id `id` from whichever leaf `pos`
+    // descends to, so a relocated particle's stale copy stops surfacing in
+    // query/nearest results immediately instead of waiting for its slot's
+    // next rebuild.
+    fn remove_in_place(&mut self, id: u64, pos: Vec2) -> bool {
+        if self.is_full {
+            if let Some(v) = self.northeast.as_mut() {
+                if v.boundary.contains(pos) {
+                    return v.remove_in_place(id, pos);
+                }
+            }
+            if let Some(v) = self.northwest.as_mut() {
+                if v.boundary.contains(pos) {
+                    return v.remove_in_place(id, pos);
+                }
+            }
+            if let Some(v) = self.southeast.as_mut() {
+                if v.boundary.contains(pos) {
+                    return v.remove_in_place(id, pos);
+                }
+            }
+            if let Some(v) = self.southwest.as_mut() {
+                if v.boundary.contains(pos) {
+                    return v.remove_in_place(id, pos);
+                }
+            }
+            return false;
+        }
+
+        if let Some(index) = self.data.iter().position(|p| p.id == id) {
+            self.data.swap_remove(index);
+            return true;
+        }
+        false
     }
 
-    fn query(&self, range: Circle) -> Vec<Particle> {
+    fn query(&self, range: Circle, mask: u32) -> Vec<Particle> {
         let mut res = Vec::new();
 
         if !range.overlaps_rect(&self.boundary) {
@@ -144,27 +422,165 @@ impl QuadTree {
         }
 
         for p in self.data.iter() {
-            if range.contains(&p.pos) {
-                res.push(p.clone());
+            if range.contains(&p.pos) && p.layer & mask != 0 {
+                res.push(*p);
+            }
+        }
+
+        if let Some(v) = &self.northwest {
+            res.extend(v.query(range, mask));
+        }
+        if let Some(v) = &self.northeast {
+            res.extend(v.query(range, mask));
+        }
+        if let Some(v) = &self.southwest {
+            res.extend(v.query(range, mask));
+        }
+        if let Some(v) = &self.southeast {
+            res.extend(v.query(range, mask));
+        }
+
+        res
+    }
+
+    fn query_rect(&self, range: Rect, mask: u32) -> Vec<Particle> {
+        let mut res = Vec::new();
+
+        if !range.overlaps(&self.boundary) {
+            return res;
+        }
+
+        for p in self.data.iter() {
+            if range.overlaps(&p.bounding_box()) && p.layer & mask != 0 {
+                res.push(*p);
+            }
+        }
+
+        if let Some(v) = &self.northwest {
+            res.extend(v.query_rect(range, mask));
+        }
+        if let Some(v) = &self.northeast {
+            res.extend(v.query_rect(range, mask));
+        }
+        if let Some(v) = &self.southwest {
+            res.extend(v.query_rect(range, mask));
+        }
+        if let Some(v) = &self.southeast {
+            res.extend(v.query_rect(range, mask));
+        }
+
+        res
+    }
+
+    // Same as `query_rect`, but also records the boundary of every node the
+    // search actually descended into, so the debug overlay can draw the
+    // pruning that happened for the last query.
+    fn query_rect_visited(&self, range: Rect, mask: u32, visited: &mut Vec<Rect>) -> Vec<Particle> {
+        let mut res = Vec::new();
+
+        if !range.overlaps(&self.boundary) {
+            return res;
+        }
+
+        visited.push(self.boundary);
+
+        for p in self.data.iter() {
+            if range.overlaps(&p.bounding_box()) && p.layer & mask != 0 {
+                res.push(*p);
             }
         }
 
         if let Some(v) = &self.northwest {
-            res.extend(v.query(range));
+            res.extend(v.query_rect_visited(range, mask, visited));
         }
         if let Some(v) = &self.northeast {
-            res.extend(v.query(range));
+            res.extend(v.query_rect_visited(range, mask, visited));
         }
         if let Some(v) = &self.southwest {
-            res.extend(v.query(range));
+            res.extend(v.query_rect_visited(range, mask, visited));
         }
         if let Some(v) = &self.southeast {
-            res.extend(v.query(range));
+            res.extend(v.query_rect_visited(range, mask, visited));
         }
 
         res
     }
 
+    pub fn nearest(&self, pos: Vec2, k: usize, mask: u32) -> Vec<Particle> {
+        let mut heap: BinaryHeap<NearestCandidate> = BinaryHeap::with_capacity(k + 1);
+        self.nearest_search(pos, k, mask, &mut heap);
+        nearest_heap_into_particles(heap)
+    }
+
+    fn nearest_search(
+        &self,
+        pos: Vec2,
+        k: usize,
+        mask: u32,
+        heap: &mut BinaryHeap<NearestCandidate>,
+    ) {
+        if k == 0 {
+            return;
+        }
+
+        if heap.len() >= k {
+            if let Some(worst) = heap.peek() {
+                if self.boundary_dist_sq(pos) > worst.dist_sq {
+                    return;
+                }
+            }
+        }
+
+        for p in self.data.iter().filter(|p| p.layer & mask != 0) {
+            let dist_sq = pos.distance_squared(p.pos);
+            if heap.len() < k {
+                heap.push(NearestCandidate {
+                    dist_sq,
+                    particle: *p,
+                });
+            } else if let Some(worst) = heap.peek() {
+                if dist_sq < worst.dist_sq {
+                    heap.pop();
+                    heap.push(NearestCandidate {
+                        dist_sq,
+                        particle: *p,
+                    });
+                }
+            }
+        }
+
+        let mut children: Vec<&QuadTree> = Vec::new();
+        if let Some(v) = &self.northwest {
+            children.push(v);
+        }
+        if let Some(v) = &self.northeast {
+            children.push(v);
+        }
+        if let Some(v) = &self.southwest {
+            children.push(v);
+        }
+        if let Some(v) = &self.southeast {
+            children.push(v);
+        }
+        children.sort_by(|a, b| {
+            a.boundary_dist_sq(pos)
+                .partial_cmp(&b.boundary_dist_sq(pos))
+                .unwrap_or(Ordering::Equal)
+        });
+
+        for child in children {
+            child.nearest_search(pos, k, mask, heap);
+        }
+    }
+
+    fn boundary_dist_sq(&self, pos: Vec2) -> f32 {
+        let clamped = Vec2::new(
+            pos.x.clamp(self.boundary.x, self.boundary.x + self.boundary.w),
+            pos.y.clamp(self.boundary.y, self.boundary.y + self.boundary.h),
+        );
+        pos.distance_squared(clamped)
+    }
+
     pub fn display(&mut self, thickness: f32, color: Color) {
         draw_rectangle_lines(
             self.boundary.x,
@@ -183,6 +599,219 @@ impl QuadTree {
     }
 }
 
+// One particle buffered inside a `ForestSlot`, tagged with whether it's
+// still live. A removed particle is left in place as a tombstone until its
+// slot is rebuilt, rather than rebuilding the slot's tree immediately.
+struct ForestEntry {
+    particle: Particle,
+    alive: bool,
+}
+
+// The i-th slot of a `QuadForest` holds a tree built from exactly the
+// entries it was given at construction time (2^i of them, following the
+// forest's binary-counter merge), some of which may since have been
+// tombstoned.
+struct ForestSlot {
+    tree: QuadTree,
+    entries: Vec<ForestEntry>,
+}
+
+impl ForestSlot {
+    fn new(entries: Vec<ForestEntry>, boundary: Rect) -> ForestSlot {
+        let mut tree = QuadTree::new(QUADTREE_CAPACITY, boundary, QUADTREE_MAX_DEPTH);
+        for entry in entries.iter().filter(|entry| entry.alive) {
+            tree.insert(entry.particle);
+        }
+        ForestSlot { tree, entries }
+    }
+
+    fn live_count(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.alive).count()
+    }
+}
+
+// Amortized spatial index: a forest of quadtrees where slot i is empty or
+// holds 2^i particles, ported from the kd-forest binary-counter merge.
+// Inserting a particle only ever rebuilds the handful of slots that carry
+// across, instead of rebuilding one tree from every particle every frame.
+// Movement is soft-delete-then-insert: `remove` tombstones the particle in
+// place, and its slot is only rebuilt once under half its entries are live.
+struct QuadForest {
+    boundary: Rect,
+    slots: Vec<Option<ForestSlot>>,
+    // Slot/entry index plus the boundary of the leaf the particle last
+    // landed in, so `update_in_place` can tell in O(1) whether a moved
+    // particle is still inside that leaf without touching any tree.
+    locations: HashMap<u64, (usize, usize, Rect)>,
+}
+
+impl QuadForest {
+    pub fn new(boundary: Rect) -> QuadForest {
+        QuadForest {
+            boundary,
+            slots: Vec::new(),
+            locations: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, particle: Particle) {
+        let mut carry = vec![ForestEntry {
+            particle,
+            alive: true,
+        }];
+        let mut slot_index = 0;
+
+        loop {
+            if slot_index == self.slots.len() {
+                self.slots.push(None);
+            }
+
+            if self.slots[slot_index].is_none() {
+                self.place_slot(slot_index, carry);
+                return;
+            }
+
+            let occupant = self.slots[slot_index].take().unwrap();
+            carry.extend(occupant.entries);
+            slot_index += 1;
+        }
+    }
+
+    fn place_slot(&mut self, slot_index: usize, entries: Vec<ForestEntry>) {
+        let slot = ForestSlot::new(entries, self.boundary);
+        for (entry_index, entry) in slot.entries.iter().enumerate() {
+            if entry.alive {
+                let leaf_boundary = slot.tree.leaf_boundary(entry.particle.pos);
+                self.locations
+                    .insert(entry.particle.id, (slot_index, entry_index, leaf_boundary));
+            }
+        }
+        self.slots[slot_index] = Some(slot);
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        let (slot_index, entry_index, _) = match self.locations.remove(&id) {
+            Some(location) => location,
+            None => return,
+        };
+
+        let slot = match self.slots[slot_index].as_mut() {
+            Some(slot) => slot,
+            None => return,
+        };
+
+        let pos = slot.entries[entry_index].particle.pos;
+        slot.entries[entry_index].alive = false;
+        slot.tree.remove_in_place(id, pos);
+
+        if slot.live_count() * 2 < slot.entries.len() {
+            self.rebuild_slot(slot_index);
+        }
+    }
+
+    fn rebuild_slot(&mut self, slot_index: usize) {
+        let slot = match self.slots[slot_index].take() {
+            Some(slot) => slot,
+            None => return,
+        };
+
+        let live_entries: Vec<ForestEntry> = slot
+            .entries
+            .into_iter()
+            .filter(|entry| entry.alive)
+            .collect();
+
+        // Reinsert the survivors one at a time so every slot keeps holding
+        // exactly 2^i entries.
+        for entry in live_entries {
+            self.insert(entry.particle);
+        }
+    }
+
+    // Soft-delete the particle's previous position and insert its new one.
+    pub fn relocate(&mut self, id: u64, particle: Particle) {
+        self.remove(id);
+        self.insert(particle);
+    }
+
+    // Cheap path for a particle that moved but is still inside the leaf
+    // boundary it was last placed in: overwrites it in place, touching only
+    // that one slot's tree and skipping `relocate`'s remove/insert (and the
+    // merge/rebuild bookkeeping that can trigger) entirely. Returns false
+    // if the particle has left its cached leaf, so the caller can fall
+    // back to `relocate`.
+    pub fn update_in_place(&mut self, id: u64, particle: Particle) -> bool {
+        let Some(&(slot_index, entry_index, leaf_boundary)) = self.locations.get(&id) else {
+            return false;
+        };
+
+        if !leaf_boundary.contains(particle.pos) {
+            return false;
+        }
+
+        let Some(slot) = self.slots[slot_index].as_mut() else {
+            return false;
+        };
+
+        if !slot.tree.update_in_place(id, particle) {
+            return false;
+        }
+
+        slot.entries[entry_index].particle = particle;
+        true
+    }
+
+    fn query(&self, range: Circle, mask: u32) -> Vec<Particle> {
+        self.slots
+            .iter()
+            .flatten()
+            .flat_map(|slot| slot.tree.query(range, mask))
+            .collect()
+    }
+
+    fn query_rect(&self, range: Rect, mask: u32) -> Vec<Particle> {
+        self.slots
+            .iter()
+            .flatten()
+            .flat_map(|slot| slot.tree.query_rect(range, mask))
+            .collect()
+    }
+
+    fn query_rect_visited(&self, range: Rect, mask: u32) -> (Vec<Particle>, Vec<Rect>) {
+        let mut res = Vec::new();
+        let mut visited = Vec::new();
+        for slot in self.slots.iter().flatten() {
+            res.extend(slot.tree.query_rect_visited(range, mask, &mut visited));
+        }
+        (res, visited)
+    }
+
+    pub fn nearest(&self, pos: Vec2, k: usize, mask: u32) -> Vec<Particle> {
+        let mut heap: BinaryHeap<NearestCandidate> = BinaryHeap::with_capacity(k + 1);
+        for slot in self.slots.iter().flatten() {
+            slot.tree.nearest_search(pos, k, mask, &mut heap);
+        }
+        nearest_heap_into_particles(heap)
+    }
+
+    pub fn display(&mut self, thickness: f32, color: Color) {
+        for slot in self.slots.iter_mut().flatten() {
+            slot.tree.display(thickness, color);
+        }
+    }
+}
+
+fn build_forest(points: &[Particle], boundary: Rect) -> QuadForest {
+    let mut ordered: Vec<Particle> = points.to_vec();
+    sort_by_hilbert_index(&mut ordered);
+
+    let mut forest = QuadForest::new(boundary);
+    for point in &ordered {
+        forest.insert(*point);
+    }
+    forest
+}
+
 fn generate_random_points(number_of_points: f32) -> Vec<Particle> {
     let mut points: Vec<Particle> = Vec::new();
     let mut rng = rand::thread_rng();
@@ -190,14 +819,19 @@ fn generate_random_points(number_of_points: f32) -> Vec<Particle> {
     let mut rand_x = screen_width() / 2.0;
     let mut rand_y = screen_height() / 2.0;
 
-    for _ in 0..number_of_points as i32 {
+    for index in 0..number_of_points as i32 {
+        let angle = rng.gen_range(0.0..TAU);
+        let layer = 1u32 << (index as usize % LAYER_COLORS.len());
         points.push(Particle {
+            id: next_particle_id(),
             pos: Vec2 {
                 x: rand_x,
                 y: rand_y,
             },
-            color: POINT_COLOR,
+            velocity: Vec2::new(PARTICLE_SPEED * angle.cos(), PARTICLE_SPEED * angle.sin()),
+            color: layer_color(layer),
             radius: POINT_RADIUS,
+            layer,
         });
         rand_x =
             (rand_x + rng.gen_range(-RANDOM_WALK_DISTANCE..RANDOM_WALK_DISTANCE) + screen_width())
@@ -216,15 +850,19 @@ fn draw_points(points: &Vec<Particle>) {
 }
 
 fn move_points(points: &mut Vec<Particle>) {
-    let mut rng = rand::thread_rng();
-
     for point in points {
-        let angle = rng.gen_range(0.0..TAU);
-        point.update_pos(Vec2 {
-            x: (point.pos.x + RANDOM_WALK_DISTANCE * angle.cos() + screen_width()) % screen_width(),
-            y: (point.pos.y + RANDOM_WALK_DISTANCE * angle.sin() + screen_height())
-                % screen_height(),
-        });
+        let mut new_pos = point.pos + point.velocity;
+
+        if new_pos.x < point.radius || new_pos.x > screen_width() - point.radius {
+            point.velocity.x = -point.velocity.x;
+            new_pos.x = point.pos.x + point.velocity.x;
+        }
+        if new_pos.y < point.radius || new_pos.y > screen_height() - point.radius {
+            point.velocity.y = -point.velocity.y;
+            new_pos.y = point.pos.y + point.velocity.y;
+        }
+
+        point.update_pos(new_pos);
     }
 }
 
@@ -244,35 +882,29 @@ fn move_points(points: &mut Vec<Particle>) {
 //     }
 // }
 
-fn check_overlap(points: &mut Vec<Particle>, quadtree: QuadTree) {
+fn check_overlap(points: &mut Vec<Particle>, forest: &QuadForest) {
     for index in 0..points.len() {
-        let overlap = quadtree.query(Circle {
-            x: points[index].pos.x,
-            y: points[index].pos.y,
-            r: 2.0 * points[index].radius,
-        });
+        let search_box = points[index].bounding_box();
+        let overlap = forest.query_rect(
+            Rect::new(
+                search_box.x - search_box.w / 2.0,
+                search_box.y - search_box.h / 2.0,
+                search_box.w * 2.0,
+                search_box.h * 2.0,
+            ),
+            points[index].layer,
+        );
         if overlap.len() > 1 {
             points[index].color = BLUE;
             // for index_2 in 0..overlap.len() {
             //     overlap[index_2].color = BLUE;
             // }
         } else {
-            points[index].color = RED;
+            points[index].color = layer_color(points[index].layer);
         }
     }
 }
 
-fn build_quadtree(points: &mut Vec<Particle>) -> QuadTree {
-    let mut quadtree = QuadTree::new(
-        QUADTREE_CAPACITY,
-        Rect::new(0.0, 0.0, screen_width(), screen_height()),
-    );
-    for index in 0..points.len() {
-        quadtree.insert(points[index]);
-    }
-    quadtree
-}
-
 fn window_conf() -> Conf {
     Conf {
         window_title: "Quadtree Visualizer".to_owned(),
@@ -285,24 +917,83 @@ fn window_conf() -> Conf {
 #[macroquad::main(window_conf)]
 async fn main() {
     let mut points = generate_random_points(NUMBER_OF_POINTS);
+    let screen_bounds = Rect::new(0.0, 0.0, screen_width(), screen_height());
+    let mut forest = build_forest(&points, screen_bounds);
+    let hilbert_curve_preview = hilbert_curve_preview_points();
+    let mut debug_mode = false;
+    let mut hilbert_color_mode = false;
+    let mut nearest_mode = false;
 
     loop {
         clear_background(GRAY);
 
         if is_key_pressed(KeyCode::Space) {
             points = generate_random_points(NUMBER_OF_POINTS);
+            forest = build_forest(&points, screen_bounds);
+        }
+        if is_key_pressed(KeyCode::D) {
+            debug_mode = !debug_mode;
+        }
+        if is_key_pressed(KeyCode::H) {
+            hilbert_color_mode = !hilbert_color_mode;
+        }
+        if is_key_pressed(KeyCode::N) {
+            nearest_mode = !nearest_mode;
         }
 
         move_points(&mut points);
+        for point in points.iter() {
+            if !forest.update_in_place(point.id, *point) {
+                forest.relocate(point.id, *point);
+            }
+        }
 
-        let mut quadtree = build_quadtree(&mut points);
-        quadtree.display(4.0, GREEN);
+        forest.display(4.0, GREEN);
 
-        // check_overlap(&mut points);
-        check_overlap(&mut points, quadtree);
+        if debug_mode {
+            let (mouse_x, mouse_y) = mouse_position();
+            let (_, visited) = forest.query_rect_visited(
+                Rect::new(
+                    mouse_x - DEBUG_QUERY_RADIUS,
+                    mouse_y - DEBUG_QUERY_RADIUS,
+                    DEBUG_QUERY_RADIUS * 2.0,
+                    DEBUG_QUERY_RADIUS * 2.0,
+                ),
+                ALL_LAYERS,
+            );
+            for node in visited {
+                draw_rectangle_lines(node.x, node.y, node.w, node.h, 4.0, DEBUG_NODE_COLOR);
+            }
+        }
+
+        if hilbert_color_mode {
+            for point in points.iter_mut() {
+                point.color = hilbert_color(hilbert_index(point.pos));
+            }
+            for pair in hilbert_curve_preview.windows(2) {
+                draw_line(pair[0].x, pair[0].y, pair[1].x, pair[1].y, 1.0, BLACK);
+            }
+        } else {
+            // check_overlap(&mut points);
+            check_overlap(&mut points, &forest);
+        }
 
         draw_points(&points);
 
+        if nearest_mode {
+            let (mouse_x, mouse_y) = mouse_position();
+            let nearest = forest.nearest(Vec2::new(mouse_x, mouse_y), NEAREST_K, ALL_LAYERS);
+            for point in nearest {
+                draw_circle_lines(
+                    point.pos.x,
+                    point.pos.y,
+                    point.radius + 4.0,
+                    2.0,
+                    NEAREST_HIGHLIGHT_COLOR,
+                );
+            }
+        }
+
         let fps_text = format!("{}", get_fps());
         draw_text_ex(
             &fps_text,
@@ -343,3 +1034,47 @@ async fn main() {
 //         ..Default::default()
 //     },
 // );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn particle(id: u64, pos: Vec2) -> Particle {
+        Particle {
+            id,
+            pos,
+            velocity: Vec2::ZERO,
+            color: RED,
+            radius: POINT_RADIUS,
+            layer: ALL_LAYERS,
+        }
+    }
+
+    #[test]
+    fn relocate_does_not_leave_a_stale_copy_at_the_old_position() {
+        let boundary = Rect::new(0.0, 0.0, 400.0, 400.0);
+        let mut forest = QuadForest::new(boundary);
+
+        for id in 0..40 {
+            forest.insert(particle(id, Vec2::new(10.0, 10.0)));
+        }
+
+        let old_pos = Vec2::new(10.0, 10.0);
+        let new_pos = Vec2::new(390.0, 390.0);
+        forest.relocate(0, particle(0, new_pos));
+
+        let ghost_count = forest
+            .query_rect(Rect::new(old_pos.x - 5.0, old_pos.y - 5.0, 10.0, 10.0), ALL_LAYERS)
+            .into_iter()
+            .filter(|p| p.id == 0)
+            .count();
+        assert_eq!(ghost_count, 0);
+
+        let found_count = forest
+            .query_rect(Rect::new(new_pos.x - 5.0, new_pos.y - 5.0, 10.0, 10.0), ALL_LAYERS)
+            .into_iter()
+            .filter(|p| p.id == 0)
+            .count();
+        assert_eq!(found_count, 1);
+    }
+}